@@ -2,22 +2,32 @@ extern crate clap;
 extern crate csv;
 extern crate rand;
 extern crate hyper;
+extern crate futures;
 extern crate url;
+extern crate serde_json;
 
 mod storage;
 mod util;
 
 use serde::Deserialize;
 use clap::{App, Arg};
-use hyper::{Body, Response, Server};
+use hyper::{Body, Request, Response, Server, StatusCode};
 use hyper::rt::Future;
-use hyper::service::{service_fn_ok};
+use hyper::server::conn::AddrStream;
+use hyper::service::{service_fn_ok, make_service_fn};
+use futures::future;
+use std::cell::Cell;
+use std::fs;
 use std::fs::File;
 use std::net::SocketAddr;
-use storage::{Storage, InMemoryStorage};
+use std::path::Path;
+use std::time::Duration;
+use storage::{SelectedBanner, Storage, InMemoryStorage};
 use std::sync::Arc;
 use url::Url;
-
+use util::byte_range::{self, RangeError};
+use util::mime;
+use util::rate_limiter::GcraLimiter;
 
 fn main() {
     let args = App::new("Banners rotator")
@@ -30,6 +40,18 @@ fn main() {
             .long("port")
             .help("Listening HTTP port")
             .default_value("8080"))
+        .arg(Arg::with_name("rate")
+            .long("rate")
+            .help("Requests per second allowed for a single client IP")
+            .default_value("10"))
+        .arg(Arg::with_name("burst")
+            .long("burst")
+            .help("Extra requests a client may burst on top of --rate")
+            .default_value("5"))
+        .arg(Arg::with_name("trust_proxy")
+            .long("trust-proxy")
+            .help("Rate-limit by the first X-Forwarded-For hop instead of the peer address; only enable behind a trusted reverse proxy that overwrites client-supplied XFF")
+            .takes_value(false))
         .get_matches();
 
     let config_file = File::open(args.value_of("FILE").unwrap()).expect("Can't open config file");
@@ -43,7 +65,8 @@ fn main() {
     for record_result in reader.deserialize() {
         let record: BannerRecord = record_result.expect("CSV deserialization error");
         let record_dup = record.clone();
-        if let Err(e) = initializable_banners.add_banner(record.url, record.shows_amount, record.categories) {
+        let (categories, exclude_categories) = split_categories(record.categories);
+        if let Err(e) = initializable_banners.add_banner(record.url, record.shows_amount, categories, exclude_categories) {
             eprintln!("Banners {:?} isn't added: {}", record_dup, e);
         }
     }
@@ -56,11 +79,25 @@ fn main() {
 
     let base_url = Arc::new(Url::parse("http://localhost").unwrap());
 
-    let service = move || {
+    let rate: u32 = args.value_of("rate").unwrap().parse().expect("Illegal rate");
+    let burst: u32 = args.value_of("burst").unwrap().parse().expect("Illegal burst");
+    let limiter = Arc::new(GcraLimiter::new(rate, Duration::from_secs(1), burst));
+    let trust_proxy = args.is_present("trust_proxy");
+
+    let make_svc = make_service_fn(move |socket: &AddrStream| {
         let storage = banners.clone();
         let base = base_url.clone();
+        let limiter = limiter.clone();
+        let peer_addr = socket.remote_addr();
+
+        future::ok::<_, hyper::Error>(service_fn_ok(move |req| {
+            if !limiter.allow(client_ip(&req, peer_addr, trust_proxy)) {
+                return Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(Body::empty())
+                    .unwrap();
+            }
 
-        service_fn_ok(move |req| {
             let uri = req.uri().to_string();
             let url = base.join(uri.as_str()).unwrap();
             let categories = url.query_pairs().filter_map(|(param, val)| {
@@ -71,14 +108,21 @@ fn main() {
                 }
             }).collect::<Vec<String>>();
 
-            storage.get_banner_html(categories)
-                .map_or_else(|| Response::builder().status(204).body(Body::empty()).unwrap(),
-                             |html| Response::new(Body::from(html)))
-        })
-    };
+            if url.path() == "/banner" {
+                serve_banner(&storage, categories, &req)
+            } else {
+                let wants_redirect = url.query_pairs().any(|(param, val)| param == "redirect" && val != "0");
+                let accept = req.headers().get(hyper::header::ACCEPT)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("");
+
+                respond_with_banner(storage.get_banner(categories), wants_redirect, accept)
+            }
+        }))
+    });
 
     let server = Server::bind(&bind_addr)
-        .serve(service)
+        .serve(make_svc)
         .map_err(|e| eprintln!("Server error: {}", e));
 
     println!("Start listening on {}", &bind_addr);
@@ -86,6 +130,179 @@ fn main() {
 
 }
 
+const HTML_PREFIX: &str = r#"<html><body><img src=""#;
+const HTML_SUFFIX: &str = r#""/></body></html>"#;
+
+/// Picks a representation for a selected banner based on what the client
+/// asked for: a `302` redirect to the banner URL, a JSON payload for
+/// `Accept: application/json`, or the original HTML `<img>` wrapper.
+fn respond_with_banner(selected: Option<SelectedBanner>, wants_redirect: bool, accept: &str) -> Response<Body> {
+    let selected = match selected {
+        Some(selected) => selected,
+        None => return Response::builder().status(204).body(Body::empty()).unwrap(),
+    };
+
+    if wants_redirect {
+        return Response::builder()
+            .status(StatusCode::FOUND)
+            .header(hyper::header::LOCATION, selected.url.as_str())
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    if accept.contains("application/json") {
+        let body = serde_json::to_string(&selected).expect("Banner JSON serialization error");
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    } else {
+        let html = format!("{}{}{}", HTML_PREFIX, selected.url, HTML_SUFFIX);
+        Response::new(Body::from(html))
+    }
+}
+
+/// Resolves the client IP a rate-limit bucket should be keyed on. Only
+/// trusts the `X-Forwarded-For` header's first hop when `trust_proxy` is
+/// set (i.e. the operator has confirmed every connection is relayed
+/// through a proxy that overwrites any client-supplied XFF) — otherwise a
+/// client hitting this listener directly could fabricate a fresh XFF
+/// per request and dodge the rate limit entirely. Always falls back to
+/// the connection's own peer address.
+fn client_ip(req: &Request<Body>, peer_addr: SocketAddr, trust_proxy: bool) -> std::net::IpAddr {
+    if trust_proxy {
+        if let Some(ip) = req.headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|first| first.trim().parse().ok())
+        {
+            return ip;
+        }
+    }
+
+    peer_addr.ip()
+}
+
+/// What went wrong trying to turn a selected banner's `url` into bytes on
+/// the wire, kept separate from "no banner matched" so `serve_banner` can
+/// report the right status without having consumed an impression for it.
+enum BannerFileError {
+    NotFound,
+    Unsatisfiable { total_len: u64 },
+}
+
+/// A banner file ready to be written to the response, already sliced to
+/// the requested range (if any).
+struct PreparedFile {
+    bytes: Vec<u8>,
+    content_type: &'static str,
+    total_len: u64,
+    range: Option<byte_range::ByteRange>,
+}
+
+/// Reads the banner's backing file and, if a `Range` header is present,
+/// validates and applies it. Pure preparation — never consumes an
+/// impression, so it's safe to call before the banner is "shown".
+fn prepare_banner_file(banner_url: &str, range_header: Option<&str>) -> Result<PreparedFile, BannerFileError> {
+    let bytes = fs::read(Path::new(banner_url)).map_err(|_| BannerFileError::NotFound)?;
+    let content_type = mime::from_path(banner_url);
+    let total_len = bytes.len() as u64;
+
+    match range_header.map(|header| byte_range::parse(header, total_len)) {
+        Some(Ok(range)) => {
+            let slice = bytes[range.start as usize..=range.end as usize].to_vec();
+            Ok(PreparedFile { bytes: slice, content_type, total_len, range: Some(range) })
+        }
+        Some(Err(RangeError::Unsatisfiable)) => Err(BannerFileError::Unsatisfiable { total_len }),
+        Some(Err(RangeError::Malformed)) | None => Ok(PreparedFile { bytes, content_type, total_len, range: None }),
+    }
+}
+
+fn banner_file_response(prepared: PreparedFile) -> Response<Body> {
+    match prepared.range {
+        Some(range) => {
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(hyper::header::CONTENT_TYPE, prepared.content_type)
+                .header(hyper::header::CONTENT_LENGTH, prepared.bytes.len().to_string())
+                .header(hyper::header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, prepared.total_len))
+                .body(Body::from(prepared.bytes))
+                .unwrap()
+        }
+        None => {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(hyper::header::CONTENT_TYPE, prepared.content_type)
+                .header(hyper::header::CONTENT_LENGTH, prepared.total_len.to_string())
+                .body(Body::from(prepared.bytes))
+                .unwrap()
+        }
+    }
+}
+
+/// Streams a selected banner's `url` as the local file it points at,
+/// rather than wrapping it in an HTML `<img>` tag. Honors a `Range`
+/// request header so large creatives can be fetched in chunks.
+///
+/// The banner's file is read and its Range validated *before* the
+/// impression is consumed, so a missing file or an unsatisfiable range
+/// never decrements `shows_left` for a banner that wasn't actually served.
+fn serve_banner(storage: &InMemoryStorage, categories: Vec<String>, req: &Request<Body>) -> Response<Body> {
+    let range_header = req.headers().get(hyper::header::RANGE).and_then(|v| v.to_str().ok());
+    let failure = Cell::new(None);
+
+    let served = storage.get_banner_if(categories, |banner_url| {
+        match prepare_banner_file(banner_url, range_header) {
+            Ok(prepared) => Some(prepared),
+            Err(e) => {
+                failure.set(Some(e));
+                None
+            }
+        }
+    });
+
+    match served {
+        Some((_, prepared)) => banner_file_response(prepared),
+        None => match failure.into_inner() {
+            Some(BannerFileError::NotFound) => Response::builder().status(404).body(Body::from("Banner file not found")).unwrap(),
+            Some(BannerFileError::Unsatisfiable { total_len }) => {
+                Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(hyper::header::CONTENT_RANGE, format!("bytes */{}", total_len))
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            None => Response::builder().status(204).body(Body::empty()).unwrap(),
+        }
+    }
+}
+
+/// Splits a row's trailing category columns into positive targets and
+/// exclusions. A category prefixed with `!` is an exclusion; everything
+/// else is a positive target, so `url;shows;cat1;cat2` rows without a
+/// `!`-prefixed entry keep meaning exactly what they always did. Empty
+/// entries are skipped.
+fn split_categories(raw: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut categories = Vec::new();
+    let mut exclude_categories = Vec::new();
+
+    for category in raw {
+        let category = category.trim();
+        if category.is_empty() {
+            continue;
+        }
+
+        if category.starts_with('!') {
+            exclude_categories.push(category[1..].trim().to_string());
+        } else {
+            categories.push(category.to_string());
+        }
+    }
+
+    (categories, exclude_categories)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct BannerRecord {
     url: String,
@@ -1,19 +1,40 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use serde::export::Formatter;
+use serde::Serialize;
 use super::util::cumulative_weights::CumulativeWeights;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU32, Ordering};
 
 type Category = String;
 type BannerIdx = usize;
 
-const HTML_PREFIX: &str = r#"<html><body><img src=""#;
-const HTML_SUFFIX: &str = r#""/></body></html>"#;
-
 pub trait Storage {
-    fn add_banner(&mut self, url: String, shows_amount: u32, categories: Vec<Category>) -> Result<(), StoreError>;
-    fn get_banner_html(&self, categories: Vec<Category>) -> Option<String>;
+    fn add_banner(&mut self, url: String, shows_amount: u32, categories: Vec<Category>, exclude_categories: Vec<Category>) -> Result<(), StoreError>;
+
+    ///
+    /// Selects a banner and hands its `url` to `prepare` before consuming
+    /// its impression. The impression is only decremented once `prepare`
+    /// returns `Some`, so a caller that can't actually serve the candidate
+    /// (file missing, Range unsatisfiable, ...) can return `None` and the
+    /// banner's `shows_left` is left untouched.
+    ///
+    fn get_banner_if<F, T>(&self, categories: Vec<Category>, prepare: F) -> Option<(SelectedBanner, T)>
+        where F: FnOnce(&str) -> Option<T>;
+
+    fn get_banner(&self, categories: Vec<Category>) -> Option<SelectedBanner> {
+        self.get_banner_if(categories, |_| Some(())).map(|(banner, _)| banner)
+    }
+}
+
+///
+/// A banner chosen for an impression. The service layer picks how to
+/// render this (HTML, JSON, a redirect, ...) rather than storage deciding.
+///
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SelectedBanner {
+    pub url: String,
+    pub categories: Vec<Category>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -39,31 +60,40 @@ impl std::fmt::Display for StoreError {
 #[derive(Debug)]
 struct Banner {
     url: String,
+    categories: Vec<Category>,
     shows_amount: u32,
     shows_left: Arc<AtomicU32>,
 }
 
 impl Banner {
-    fn new(url: String, shows_amount: u32) -> Self {
+    fn new(url: String, shows_amount: u32, categories: Vec<Category>) -> Self {
         Banner {
             url,
+            categories,
             shows_amount,
             shows_left: Arc::new(AtomicU32::new(shows_amount)),
         }
     }
 
-    fn show_html(&self) -> Option<String> {
-        let shows_left= &self.shows_left.clone();
+    fn try_consume(&self) -> bool {
+        let shows_left = &self.shows_left.clone();
         let mut left = shows_left.load(Ordering::SeqCst);
 
         while left > 0 && shows_left.compare_and_swap(left, left - 1, Ordering::SeqCst) != left {
             left = shows_left.load(Ordering::SeqCst);
         }
 
-        if left == 0 {
-            return None
+        left > 0
+    }
+
+    fn show(&self) -> Option<SelectedBanner> {
+        if self.try_consume() {
+            Some(SelectedBanner {
+                url: self.url.clone(),
+                categories: self.categories.clone(),
+            })
         } else {
-            Some(format!("{}{}{}", HTML_PREFIX, self.url, HTML_SUFFIX))
+            None
         }
     }
 
@@ -77,7 +107,8 @@ impl Banner {
 pub struct InMemoryStorage {
     banners: Vec<Banner>,
     index: HashMap<Category, Vec<BannerIdx>>,
-    cumulative_weights: CumulativeWeights, // weight for banners vector used to weighted selection
+    exclude_index: HashMap<Category, Vec<BannerIdx>>,
+    cumulative_weights: Mutex<CumulativeWeights>, // weight for banners vector used to weighted selection
 }
 
 impl std::fmt::Display for InMemoryStorage {
@@ -91,13 +122,14 @@ impl InMemoryStorage {
         InMemoryStorage {
             banners: Vec::new(),
             index: HashMap::new(),
-            cumulative_weights: CumulativeWeights::new(),
+            exclude_index: HashMap::new(),
+            cumulative_weights: Mutex::new(CumulativeWeights::new()),
         }
     }
 }
 
 impl Storage for InMemoryStorage {
-    fn add_banner(&mut self, url: String, shows_amount: u32, categories: Vec<String>) -> Result<(), StoreError> {
+    fn add_banner(&mut self, url: String, shows_amount: u32, categories: Vec<String>, exclude_categories: Vec<String>) -> Result<(), StoreError> {
         if url.is_empty() {
             return Err(StoreError::IllegalUrl);
         }
@@ -110,29 +142,35 @@ impl Storage for InMemoryStorage {
             return Err(StoreError::EmptyCategories);
         }
 
-        let banner = Banner::new(url, shows_amount);
         let banner_idx = self.banners.len();
-        self.banners.push(banner);
+        for category in &categories {
+            self.index.entry(category.clone())
+                .and_modify(|indexes| indexes.push(banner_idx))
+                .or_insert_with(|| vec![banner_idx]);
+        }
 
-        for category in categories {
-            self.index.entry(category)
+        for category in &exclude_categories {
+            self.exclude_index.entry(category.clone())
                 .and_modify(|indexes| indexes.push(banner_idx))
                 .or_insert_with(|| vec![banner_idx]);
         }
 
-        self.cumulative_weights.add_weight(shows_amount);
+        self.cumulative_weights.get_mut().unwrap().add_weight(shows_amount);
+        self.banners.push(Banner::new(url, shows_amount, categories));
 
         Ok(())
     }
 
-    fn get_banner_html(&self, categories: Vec<Category>) -> Option<String> {
+    fn get_banner_if<F, T>(&self, categories: Vec<Category>, prepare: F) -> Option<(SelectedBanner, T)>
+        where F: FnOnce(&str) -> Option<T>
+    {
         match self.filter_by_categories(categories) {
             FilterResult::All => {
-                self.show_html_select_all()
+                self.select_all(prepare)
             }
             FilterResult::Slice { indexes } => {
                 let weights = self.get_cumulative_weights(&indexes);
-                self.show_html(&weights)
+                self.select(&weights, prepare)
             }
         }
     }
@@ -149,10 +187,16 @@ impl InMemoryStorage {
             return FilterResult::All;
         }
 
+        let excluded = categories.iter()
+            .filter_map(|category| self.exclude_index.get(category))
+            .flatten()
+            .collect::<HashSet<&BannerIdx>>();
+
         let indexes = categories.iter()
             .filter_map(|category| self.index.get(category))
             .flatten()
             .filter(|&idx| self.banners[*idx].can_show())
+            .filter(|idx| !excluded.contains(idx))
             .collect::<HashSet<&BannerIdx>>();
         return FilterResult::Slice { indexes };
     }
@@ -166,22 +210,47 @@ impl InMemoryStorage {
         weights
     }
 
-    fn show_html(&self, weights: &CumulativeWeights) -> Option<String> {
-        weights.select_uniformly()
-            .and_then(|idx| self.banners.get(idx))
-            .and_then(|banner| banner.show_html())
+    fn select<F, T>(&self, weights: &CumulativeWeights, prepare: F) -> Option<(SelectedBanner, T)>
+        where F: FnOnce(&str) -> Option<T>
+    {
+        let idx = weights.select_uniformly()?;
+        let banner = self.banners.get(idx)?;
+        let payload = prepare(&banner.url)?;
+        let selected = banner.show();
+        self.sync_master_weight(idx, banner);
+        selected.map(|selected| (selected, payload))
+    }
+
+    fn select_all<F, T>(&self, prepare: F) -> Option<(SelectedBanner, T)>
+        where F: FnOnce(&str) -> Option<T>
+    {
+        let idx = self.cumulative_weights.lock().unwrap().select_uniformly()?;
+        let banner = self.banners.get(idx)?;
+        let payload = prepare(&banner.url)?;
+        let selected = banner.show();
+        self.sync_master_weight(idx, banner);
+        selected.map(|selected| (selected, payload))
     }
 
-    fn show_html_select_all(&self) -> Option<String> {
-        self.cumulative_weights.select_uniformly()
-            .and_then(|idx| self.banners.get(idx))
-            .and_then(|banner| banner.show_html())
+    ///
+    /// Zeroes a banner's weight in the master `cumulative_weights` tree
+    /// the moment it runs out of shows, no matter which path consumed the
+    /// last impression. The category-filtered `select` path weighs a
+    /// per-request slice, not the master tree, so without this the master
+    /// tree would keep a depleted banner's full weight forever once it
+    /// happens to be exhausted via a category request instead of a
+    /// no-category one.
+    ///
+    fn sync_master_weight(&self, idx: BannerIdx, banner: &Banner) {
+        if !banner.can_show() {
+            self.cumulative_weights.lock().unwrap().set_weight(idx, 0);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::{InMemoryStorage, Storage, StoreError};
+    use crate::storage::{InMemoryStorage, SelectedBanner, Storage, StoreError};
 
     #[test]
     fn empty_storage() {
@@ -189,10 +258,10 @@ mod tests {
         let storage = InMemoryStorage::new();
 
         // act
-        let html = storage.get_banner_html(vec![]);
+        let banner = storage.get_banner(vec![]);
 
         // assert
-        assert_eq!(html, None)
+        assert_eq!(banner, None)
     }
 
     #[test]
@@ -201,9 +270,9 @@ mod tests {
         let mut storage = InMemoryStorage::new();
 
         // act
-        let illegal_url_res = storage.add_banner("".to_string(), 1, vec!["cat".to_string()]);
-        let illegal_shows_amount_res = storage.add_banner("some".to_string(), 0, vec!["cat".to_string()]);
-        let illegal_categories_res = storage.add_banner("some".to_string(), 1, vec![]);
+        let illegal_url_res = storage.add_banner("".to_string(), 1, vec!["cat".to_string()], vec![]);
+        let illegal_shows_amount_res = storage.add_banner("some".to_string(), 0, vec!["cat".to_string()], vec![]);
+        let illegal_categories_res = storage.add_banner("some".to_string(), 1, vec![], vec![]);
 
         // assert
         assert_eq!(illegal_url_res, Err(StoreError::IllegalUrl));
@@ -219,13 +288,12 @@ mod tests {
         let categories = vec!["example".to_string()];
 
         // act
-        let store_res = storage.add_banner(String::from(url), 1, categories);
-        let html = storage.get_banner_html(vec![]);
+        let store_res = storage.add_banner(String::from(url), 1, categories.clone(), vec![]);
+        let banner = storage.get_banner(vec![]);
 
         // assert
         assert_eq!(store_res, Ok(()));
-        assert!(html.is_some());
-        assert!(html.unwrap().contains(url));
+        assert_eq!(banner, Some(SelectedBanner { url: url.to_string(), categories }));
     }
 
     #[test]
@@ -236,18 +304,16 @@ mod tests {
         let categories = vec!["example".to_string()];
 
         // act
-        let store_res = storage.add_banner(String::from(url), 2, categories);
-        let html = storage.get_banner_html(vec![]);
-        let html2 = storage.get_banner_html(vec![]);
-        let html3 = storage.get_banner_html(vec![]);
+        let store_res = storage.add_banner(String::from(url), 2, categories, vec![]);
+        let banner1 = storage.get_banner(vec![]);
+        let banner2 = storage.get_banner(vec![]);
+        let banner3 = storage.get_banner(vec![]);
 
         // assert
         assert_eq!(store_res, Ok(()));
-        assert!(html.is_some());
-        assert!(html.unwrap().contains(url));
-        assert!(html2.is_some());
-        assert!(html2.unwrap().contains(url));
-        assert!(html3.is_none());
+        assert_banner(banner1, url);
+        assert_banner(banner2, url);
+        assert_no_banner(banner3);
     }
 
     #[test]
@@ -258,22 +324,22 @@ mod tests {
         let url2 = "http://example.com/2.jpg".to_string();
 
         // act
-        let store_res1 = storage.add_banner(url1.clone(), 2, categories(&["cat1", "cat2"]));
-        let store_res2 = storage.add_banner(url2.clone(), 1, categories(&["cat3"]));
-        let html1 = storage.get_banner_html(categories(&["cat1"]));
-        let html2 = storage.get_banner_html(categories(&["cat2"]));
-        let html3 = storage.get_banner_html(categories(&["cat1"]));
-        let html4 = storage.get_banner_html(categories(&["cat3"]));
-        let html5 = storage.get_banner_html(categories(&["cat3"]));
+        let store_res1 = storage.add_banner(url1.clone(), 2, categories(&["cat1", "cat2"]), vec![]);
+        let store_res2 = storage.add_banner(url2.clone(), 1, categories(&["cat3"]), vec![]);
+        let banner1 = storage.get_banner(categories(&["cat1"]));
+        let banner2 = storage.get_banner(categories(&["cat2"]));
+        let banner3 = storage.get_banner(categories(&["cat1"]));
+        let banner4 = storage.get_banner(categories(&["cat3"]));
+        let banner5 = storage.get_banner(categories(&["cat3"]));
 
         // assert
         assert_eq!(store_res1, Ok(()));
         assert_eq!(store_res2, Ok(()));
-        assert_html(html1, &url1);
-        assert_html(html2, &url1);
-        assert_no_html(html3);
-        assert_html(html4, &url2);
-        assert_no_html(html5);
+        assert_banner(banner1, &url1);
+        assert_banner(banner2, &url1);
+        assert_no_banner(banner3);
+        assert_banner(banner4, &url2);
+        assert_no_banner(banner5);
     }
 
     #[test]
@@ -282,12 +348,12 @@ mod tests {
         let mut storage = InMemoryStorage::new();
 
         // act
-        let store_res = storage.add_banner("url".to_string(), 1, categories(&["example"]));
-        let html = storage.get_banner_html(categories(&["unknown"]));
+        let store_res = storage.add_banner("url".to_string(), 1, categories(&["example"]), vec![]);
+        let banner = storage.get_banner(categories(&["unknown"]));
 
         // assert
         assert_eq!(store_res, Ok(()));
-        assert_no_html(html);
+        assert_no_banner(banner);
     }
 
     #[test]
@@ -296,16 +362,62 @@ mod tests {
         let mut storage = InMemoryStorage::new();
 
         // act
-        storage.add_banner("url1".to_string(), 1, categories(&["cat1"])).unwrap();
-        storage.add_banner("url2".to_string(), 1, categories(&["cat2"])).unwrap();
-        let html1 = storage.get_banner_html(categories(&["cat1", "cat2"]));
-        let html2 = storage.get_banner_html(categories(&["cat1", "cat2"]));
-        let html3 = storage.get_banner_html(categories(&["cat1", "cat2"]));
+        storage.add_banner("url1".to_string(), 1, categories(&["cat1"]), vec![]).unwrap();
+        storage.add_banner("url2".to_string(), 1, categories(&["cat2"]), vec![]).unwrap();
+        let banner1 = storage.get_banner(categories(&["cat1", "cat2"]));
+        let banner2 = storage.get_banner(categories(&["cat1", "cat2"]));
+        let banner3 = storage.get_banner(categories(&["cat1", "cat2"]));
 
         // assert
-        assert_html_one_of(html1, &["url1", "url2"]);
-        assert_html_one_of(html2, &["url1", "url2"]);
-        assert_no_html(html3);
+        assert_banner_one_of(banner1, &["url1", "url2"]);
+        assert_banner_one_of(banner2, &["url1", "url2"]);
+        assert_no_banner(banner3);
+    }
+
+    #[test]
+    fn depleting_via_a_category_request_updates_the_master_weight() {
+        // arrange
+        let mut storage = InMemoryStorage::new();
+        let url_x = "http://example.com/x.jpg".to_string();
+        let url_y = "http://example.com/y.jpg".to_string();
+
+        // act
+        storage.add_banner(url_x, 1, categories(&["x"]), vec![]).unwrap();
+        storage.add_banner(url_y.clone(), 50, categories(&["y"]), vec![]).unwrap();
+        storage.get_banner(categories(&["x"])); // exhausts the "x" banner via the category path
+
+        // assert
+        for _ in 0..50 {
+            assert_banner(storage.get_banner(vec![]), &url_y);
+        }
+    }
+
+    #[test]
+    fn exclusion_suppresses_matching_banner() {
+        // arrange
+        let mut storage = InMemoryStorage::new();
+        let url = "http://example.com/travel.jpg".to_string();
+
+        // act
+        storage.add_banner(url, 1, categories(&["travel"]), categories(&["disaster"])).unwrap();
+        let banner = storage.get_banner(categories(&["travel", "disaster"]));
+
+        // assert
+        assert_no_banner(banner);
+    }
+
+    #[test]
+    fn exclusion_does_not_affect_unrelated_requests() {
+        // arrange
+        let mut storage = InMemoryStorage::new();
+        let url = "http://example.com/travel.jpg".to_string();
+
+        // act
+        storage.add_banner(url.clone(), 1, categories(&["travel"]), categories(&["disaster"])).unwrap();
+        let banner = storage.get_banner(categories(&["travel"]));
+
+        // assert
+        assert_banner(banner, &url);
     }
 
     fn categories(cats: &[&str]) -> Vec<String> {
@@ -314,20 +426,18 @@ mod tests {
             .collect()
     }
 
-    fn assert_html(html: Option<String>, url: &String) {
-        assert!(html.is_some());
-        assert!(html.unwrap().contains(url));
+    fn assert_banner(banner: Option<SelectedBanner>, url: &str) {
+        assert!(banner.is_some());
+        assert_eq!(banner.unwrap().url, url);
     }
 
-    fn assert_html_one_of(html: Option<String>, urls: &[&str]) {
-        assert!(html.is_some());
-        let res = &html.unwrap();
-        let contains_any = urls.iter()
-            .any(|url| res.contains(url));
-        assert!(contains_any);
+    fn assert_banner_one_of(banner: Option<SelectedBanner>, urls: &[&str]) {
+        assert!(banner.is_some());
+        let res = banner.unwrap();
+        assert!(urls.iter().any(|url| res.url == *url));
     }
 
-    fn assert_no_html(html: Option<String>) {
-        assert!(html.is_none());
+    fn assert_no_banner(banner: Option<SelectedBanner>) {
+        assert!(banner.is_none());
     }
-}
\ No newline at end of file
+}
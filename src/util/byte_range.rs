@@ -0,0 +1,86 @@
+///
+/// An inclusive byte range resolved against a known resource length.
+///
+#[derive(Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeError {
+    Malformed,
+    Unsatisfiable,
+}
+
+///
+/// Parses a single `Range: bytes=start-end` header value against
+/// `content_length`, per RFC 7233 §2.1 (either bound may be omitted).
+///
+pub fn parse(header: &str, content_length: u64) -> Result<ByteRange, RangeError> {
+    if !header.starts_with("bytes=") {
+        return Err(RangeError::Malformed);
+    }
+    let spec = &header["bytes=".len()..];
+
+    let mut parts = spec.splitn(2, '-');
+    let start_str = parts.next().ok_or(RangeError::Malformed)?;
+    let end_str = parts.next().ok_or(RangeError::Malformed)?;
+
+    let (start, end) = if start_str.is_empty() {
+        let suffix_len: u64 = end_str.parse().map_err(|_| RangeError::Malformed)?;
+        if suffix_len == 0 || content_length == 0 {
+            return Err(RangeError::Unsatisfiable);
+        }
+        (content_length.saturating_sub(suffix_len), content_length - 1)
+    } else {
+        let start: u64 = start_str.parse().map_err(|_| RangeError::Malformed)?;
+        let end: u64 = if end_str.is_empty() {
+            content_length.saturating_sub(1)
+        } else {
+            end_str.parse().map_err(|_| RangeError::Malformed)?
+        };
+        (start, end)
+    };
+
+    if content_length == 0 || start >= content_length || start > end {
+        return Err(RangeError::Unsatisfiable);
+    }
+
+    Ok(ByteRange { start, end: end.min(content_length - 1) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ByteRange, RangeError};
+
+    #[test]
+    fn parses_explicit_range() {
+        assert_eq!(parse("bytes=0-99", 1000), Ok(ByteRange { start: 0, end: 99 }));
+    }
+
+    #[test]
+    fn parses_open_ended_range() {
+        assert_eq!(parse("bytes=900-", 1000), Ok(ByteRange { start: 900, end: 999 }));
+    }
+
+    #[test]
+    fn parses_suffix_range() {
+        assert_eq!(parse("bytes=-100", 1000), Ok(ByteRange { start: 900, end: 999 }));
+    }
+
+    #[test]
+    fn clamps_end_to_content_length() {
+        assert_eq!(parse("bytes=0-10000", 1000), Ok(ByteRange { start: 0, end: 999 }));
+    }
+
+    #[test]
+    fn rejects_range_past_content_length() {
+        assert_eq!(parse("bytes=1000-1010", 1000), Err(RangeError::Unsatisfiable));
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        assert_eq!(parse("not-a-range", 1000), Err(RangeError::Malformed));
+    }
+}
@@ -1,8 +1,16 @@
 use rand::prelude::*;
 
+///
+/// Weighted-selection index backed by a Fenwick tree (binary indexed
+/// tree) over per-slot weights. Unlike a plain prefix-sum vector, a
+/// single slot's weight can be raised or lowered in O(log n) without
+/// rebuilding the whole structure, and selection descends the tree in
+/// O(log n) rather than binary-searching a flat array.
+///
 #[derive(Debug)]
 pub struct CumulativeWeights {
-    weights: Vec<u64>,
+    tree: Vec<u64>, // 1-indexed; tree[0] is unused padding
+    total: u64,
     idx_projection: IdxProjection,
 }
 
@@ -19,14 +27,16 @@ enum IdxProjection {
 impl CumulativeWeights {
     pub(crate) fn new() -> Self {
         CumulativeWeights {
-            weights: Vec::new(),
+            tree: vec![0],
+            total: 0,
             idx_projection: IdxProjection::AsIs,
         }
     }
 
     pub(crate) fn with_projection() -> Self {
         CumulativeWeights {
-            weights: Vec::new(),
+            tree: vec![0],
+            total: 0,
             idx_projection: IdxProjection::Specific(Vec::new()),
         }
     }
@@ -35,38 +45,102 @@ impl CumulativeWeights {
         if self.idx_projection != IdxProjection::AsIs {
             panic!("Can't add weight without index projetion")
         }
-        let last_weight = self.weights.last().copied().unwrap_or(0);
-        self.weights.push(last_weight + weight as u64)
+        self.push_weight(weight as u64);
     }
 
     pub(crate) fn add_weight_for_idx(&mut self, weight: u32, idx: usize) {
         match self.idx_projection {
-            IdxProjection::Specific(ref mut p) => {
-                p.push(idx);
-                self.add_weight(weight);
-            }
-            _ => panic!("Can't add projection")
+            IdxProjection::Specific(ref mut p) => p.push(idx),
+            _ => panic!("Can't add projection"),
         }
+        self.push_weight(weight as u64);
+    }
+
+    ///
+    /// Sets the weight at slot `idx` (0-indexed, in insertion order) to
+    /// `new_weight`, e.g. zeroing a banner out the instant its shows are
+    /// exhausted so it stops consuming probability mass.
+    ///
+    pub(crate) fn set_weight(&mut self, idx: usize, new_weight: u64) {
+        let i = idx + 1;
+        let current = self.prefix_sum(i) - self.prefix_sum(i - 1);
+        let delta = new_weight as i64 - current as i64;
+        self.total = (self.total as i64 + delta) as u64;
+        self.update(i, delta);
     }
 
     pub(crate) fn select_uniformly(&self) -> Option<usize> {
-        if self.weights.is_empty() {
+        if self.total == 0 {
             return None;
         }
 
-        let idx = if self.weights.len() == 1 {
-            0
-        } else {
-            let max = self.weights.last().unwrap();
-            let rnd = thread_rng().gen_range(0u64, max + 1);
+        let rnd = thread_rng().gen_range(0u64, self.total);
+        let idx = self.find_by_rank(rnd);
+
+        Some(self.idx_projection.project(idx))
+    }
+
+    ///
+    /// Appends a new leaf holding `weight`. A Fenwick node's range grows
+    /// with its low bit, so a freshly appended node must absorb the
+    /// already-built sums of the lower nodes its range now covers,
+    /// rather than a plain point update (which would only ever see
+    /// `n` as it was *before* this node existed).
+    ///
+    fn push_weight(&mut self, weight: u64) {
+        let i = self.tree.len();
+        let low_bit = i & i.wrapping_neg();
+        let mut sum = weight;
+        let mut j = i - 1;
+        while j > i - low_bit {
+            sum += self.tree[j];
+            j -= j & j.wrapping_neg();
+        }
+        self.tree.push(sum);
+        self.total += weight;
+    }
+
+    fn update(&mut self, mut i: usize, delta: i64) {
+        let n = self.tree.len() - 1;
+        while i <= n {
+            self.tree[i] = (self.tree[i] as i64 + delta) as u64;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    fn prefix_sum(&self, mut i: usize) -> u64 {
+        let mut sum = 0u64;
+        while i > 0 {
+            sum += self.tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+
+    ///
+    /// Binary lifting descent: finds the smallest 0-indexed slot whose
+    /// cumulative weight exceeds `target`, in O(log n).
+    ///
+    fn find_by_rank(&self, target: u64) -> usize {
+        let n = self.tree.len() - 1;
+        let mut highest_bit = 1usize;
+        while highest_bit * 2 <= n {
+            highest_bit *= 2;
+        }
 
-            match self.weights.binary_search(&rnd) {
-                Ok(exact_idx) => exact_idx,
-                Err(insert_idx) => insert_idx,
+        let mut pos = 0usize;
+        let mut remaining = target;
+        let mut bit = highest_bit;
+        while bit > 0 {
+            let next = pos + bit;
+            if next <= n && self.tree[next] <= remaining {
+                pos = next;
+                remaining -= self.tree[next];
             }
-        };
+            bit /= 2;
+        }
 
-        Some(self.idx_projection.project(idx))
+        pos
     }
 }
 
@@ -78,3 +152,43 @@ impl IdxProjection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CumulativeWeights;
+
+    #[test]
+    fn empty_selects_nothing() {
+        let weights = CumulativeWeights::new();
+        assert_eq!(weights.select_uniformly(), None);
+    }
+
+    #[test]
+    fn single_weight_always_selected() {
+        let mut weights = CumulativeWeights::new();
+        weights.add_weight(5);
+        assert_eq!(weights.select_uniformly(), Some(0));
+    }
+
+    #[test]
+    fn zeroed_weight_is_never_selected() {
+        let mut weights = CumulativeWeights::new();
+        weights.add_weight(3);
+        weights.add_weight(4);
+        weights.set_weight(0, 0);
+
+        for _ in 0..50 {
+            assert_eq!(weights.select_uniformly(), Some(1));
+        }
+    }
+
+    #[test]
+    fn projection_maps_back_to_original_index() {
+        let mut weights = CumulativeWeights::with_projection();
+        weights.add_weight_for_idx(1, 7);
+        weights.add_weight_for_idx(1, 9);
+        weights.set_weight(0, 0);
+
+        assert_eq!(weights.select_uniformly(), Some(9));
+    }
+}
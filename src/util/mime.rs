@@ -0,0 +1,32 @@
+///
+/// Guesses a `Content-Type` from a file's extension, defaulting to
+/// `application/octet-stream` for anything unrecognized.
+///
+pub fn from_path(path: &str) -> &'static str {
+    let ext = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_path;
+
+    #[test]
+    fn detects_known_extensions() {
+        assert_eq!(from_path("/banners/a.png"), "image/png");
+        assert_eq!(from_path("/banners/a.JPG"), "image/jpeg");
+        assert_eq!(from_path("/banners/a.jpeg"), "image/jpeg");
+        assert_eq!(from_path("/banners/a.gif"), "image/gif");
+    }
+
+    #[test]
+    fn falls_back_to_octet_stream() {
+        assert_eq!(from_path("/banners/a.bin"), "application/octet-stream");
+        assert_eq!(from_path("/banners/no_extension"), "application/octet-stream");
+    }
+}
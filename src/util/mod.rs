@@ -0,0 +1,4 @@
+pub mod cumulative_weights;
+pub mod rate_limiter;
+pub mod mime;
+pub mod byte_range;
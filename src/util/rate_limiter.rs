@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+///
+/// Per-client throttle implementing the Generic Cell Rate Algorithm (GCRA).
+///
+/// Each client IP gets a "theoretical arrival time" (TAT): the instant by
+/// which its next request is expected, given the configured steady rate.
+/// A request is accepted when it doesn't arrive more than `burst` emission
+/// intervals ahead of that schedule, which lets a client burst a bit while
+/// still being capped to the configured average rate over time.
+///
+pub struct GcraLimiter {
+    emission_interval: Duration,
+    burst_tolerance: Duration,
+    tat_by_ip: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl GcraLimiter {
+    /// `rate` requests per `period`, with `burst` extra cells of tolerance.
+    pub fn new(rate: u32, period: Duration, burst: u32) -> Self {
+        let emission_interval = period / rate.max(1);
+        GcraLimiter {
+            emission_interval,
+            burst_tolerance: emission_interval * burst,
+            tat_by_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `true` if a request from `ip` arriving now should be allowed.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut tat_by_ip = self.tat_by_ip.lock().unwrap();
+        let tat = tat_by_ip.get(&ip).copied().unwrap_or(now);
+
+        if now + self.burst_tolerance < tat {
+            return false;
+        }
+
+        let new_tat = std::cmp::max(tat, now) + self.emission_interval;
+        tat_by_ip.insert(ip, new_tat);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GcraLimiter;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    fn client() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    #[test]
+    fn allows_up_to_burst_immediately() {
+        // arrange
+        let limiter = GcraLimiter::new(1, Duration::from_secs(1), 2);
+        let ip = client();
+
+        // act + assert
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+    }
+
+    #[test]
+    fn different_clients_are_independent() {
+        // arrange
+        let limiter = GcraLimiter::new(1, Duration::from_secs(1), 0);
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        // act
+        let a_first = limiter.allow(a);
+        let b_first = limiter.allow(b);
+
+        // assert
+        assert!(a_first);
+        assert!(b_first);
+    }
+}